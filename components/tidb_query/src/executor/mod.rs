@@ -26,8 +26,10 @@ use codec::prelude::NumberDecoder;
 use tidb_query_datatype::prelude::*;
 use tidb_query_datatype::FieldTypeFlag;
 use tikv_util::collections::HashSet;
+use kvproto::coprocessor::KeyRange;
 use tipb::ColumnInfo;
-use tipb::{Expr, ExprType};
+use tipb::ScalarFuncSig;
+use tipb::{ExecType, Expr, ExprType};
 
 use crate::codec::datum::{self, Datum, DatumEncoder};
 use crate::codec::table::{self, RowColsDict};
@@ -80,6 +82,309 @@ impl ExprColumnRefVisitor {
     }
 }
 
+/// Computes the minimal set of column ids a query actually touches, so scans
+/// can avoid decoding column values that are never referenced.
+///
+/// It runs [`ExprColumnRefVisitor`] over every expression the query evaluates
+/// — selection `conditions`, `group_by` keys, aggregation arguments
+/// (`aggregates`), and topn `order_by` exprs — unions the referenced offsets
+/// with the DAG's `output_offsets`, and translates them to column ids. The
+/// pk-handle column and any `NOT_NULL` columns are always retained for the
+/// correctness checks performed downstream when materializing rows.
+pub fn referenced_column_ids(
+    columns: &[ColumnInfo],
+    output_offsets: &[u32],
+    conditions: &[Expr],
+    group_by: &[Expr],
+    aggregates: &[Expr],
+    order_by: &[Expr],
+) -> Result<HashSet<i64>> {
+    let mut visitor = ExprColumnRefVisitor::new(columns.len());
+    for exprs in &[conditions, group_by, aggregates, order_by] {
+        visitor.batch_visit(exprs)?;
+    }
+
+    let mut ids = HashSet::default();
+    for offset in visitor.column_offsets() {
+        ids.insert(columns[offset].get_column_id());
+    }
+    for &offset in output_offsets {
+        if let Some(col) = columns.get(offset as usize) {
+            ids.insert(col.get_column_id());
+        }
+    }
+    for col in columns {
+        if col.get_pk_handle() || col.as_accessor().flag().contains(FieldTypeFlag::NOT_NULL) {
+            ids.insert(col.get_column_id());
+        }
+    }
+    Ok(ids)
+}
+
+/// Collects the referenced column ids for a whole DAG.
+///
+/// It walks the executor descriptors, gathering the selection conditions,
+/// aggregation group-by keys and argument exprs, and topn order-by exprs, then
+/// defers to [`referenced_column_ids`] to union them with `output_offsets` and
+/// the always-retained columns. It only computes the set: `ExecutorsRunner`
+/// does not call it, and nothing passes the set to the scan executors.
+pub fn dag_referenced_column_ids(
+    columns: &[ColumnInfo],
+    output_offsets: &[u32],
+    executors: &[tipb::Executor],
+) -> Result<HashSet<i64>> {
+    let mut conditions = Vec::new();
+    let mut group_by = Vec::new();
+    let mut aggregates = Vec::new();
+    let mut order_by = Vec::new();
+    for exec in executors {
+        match exec.get_tp() {
+            ExecType::TypeSelection => {
+                conditions.extend_from_slice(exec.get_selection().get_conditions());
+            }
+            ExecType::TypeAggregation | ExecType::TypeStreamAgg => {
+                let agg = exec.get_aggregation();
+                group_by.extend_from_slice(agg.get_group_by());
+                aggregates.extend_from_slice(agg.get_agg_func());
+            }
+            ExecType::TypeTopN => {
+                for item in exec.get_topN().get_order_by() {
+                    order_by.push(item.get_expr().clone());
+                }
+            }
+            _ => {}
+        }
+    }
+    referenced_column_ids(columns, output_offsets, &conditions, &group_by, &aggregates, &order_by)
+}
+
+/// A closed integer interval `[lo, hi]` over primary-key handle values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HandleRange {
+    pub lo: i64,
+    pub hi: i64,
+}
+
+impl HandleRange {
+    /// The unconstrained interval covering every representable handle.
+    fn full() -> HandleRange {
+        HandleRange {
+            lo: i64::min_value(),
+            hi: i64::max_value(),
+        }
+    }
+
+    /// An interval that no handle can satisfy.
+    fn empty() -> HandleRange {
+        HandleRange {
+            lo: i64::max_value(),
+            hi: i64::min_value(),
+        }
+    }
+
+    /// True when the interval is empty, i.e. no handle can satisfy it.
+    fn is_empty(self) -> bool {
+        self.lo > self.hi
+    }
+
+    /// Tightens this interval to its intersection with `other`.
+    fn intersect(&mut self, other: HandleRange) {
+        self.lo = self.lo.max(other.lo);
+        self.hi = self.hi.min(other.hi);
+    }
+
+    /// Converts the interval to the scan `KeyRange`s covering it, intersected
+    /// with `outer`. An empty interval yields no ranges (an empty scan).
+    pub fn to_key_ranges(self, table_id: i64, outer: &[KeyRange]) -> Vec<KeyRange> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let start = table::encode_row_key(table_id, self.lo);
+        // `hi` is inclusive; make the upper bound exclusive.
+        let mut end = table::encode_row_key(table_id, self.hi);
+        util::convert_to_prefix_next(&mut end);
+
+        let mut ranges = Vec::with_capacity(outer.len());
+        for r in outer {
+            let lo = std::cmp::max(start.as_slice(), r.get_start());
+            let hi = std::cmp::min(end.as_slice(), r.get_end());
+            if lo < hi {
+                let mut range = KeyRange::default();
+                range.set_start(lo.to_vec());
+                range.set_end(hi.to_vec());
+                ranges.push(range);
+            }
+        }
+        ranges
+    }
+}
+
+/// Walks a selection's conjunctive predicate list and folds any
+/// `pk_handle <op> const` comparisons into a single [`HandleRange`].
+///
+/// It mirrors [`ExprColumnRefVisitor`] in resolving a `ColumnRef` offset to a
+/// [`ColumnInfo`], but only recognizes top-level comparisons against the
+/// primary-key handle column (`op` ∈ {`=`, `>`, `>=`, `<`, `<=`} with an
+/// integer constant on the other side). Predicates that are fully converted
+/// are left out of the returned `residual` list; every other predicate stays
+/// in it.
+pub struct HandleRangeDetector<'a> {
+    columns: &'a [ColumnInfo],
+    range: HandleRange,
+}
+
+impl<'a> HandleRangeDetector<'a> {
+    pub fn new(columns: &'a [ColumnInfo]) -> HandleRangeDetector<'a> {
+        HandleRangeDetector {
+            columns,
+            range: HandleRange::full(),
+        }
+    }
+
+    /// Analyzes `conditions`, folding handle predicates into the interval and
+    /// returning the tightened interval together with the predicates that must
+    /// remain in the residual `SelectionExecutor` filter.
+    pub fn analyze(mut self, conditions: Vec<Expr>) -> (HandleRange, Vec<Expr>) {
+        let mut residual = Vec::with_capacity(conditions.len());
+        for cond in conditions {
+            match self.fold_cond(&cond) {
+                Some(bound) => self.range.intersect(bound),
+                None => residual.push(cond),
+            }
+        }
+        (self.range, residual)
+    }
+
+    /// Returns the interval implied by `cond` if it is a handle comparison
+    /// against an integer constant, or `None` if it must stay in the filter.
+    fn fold_cond(&self, cond: &Expr) -> Option<HandleRange> {
+        if cond.get_tp() != ExprType::ScalarFunc {
+            return None;
+        }
+        let children = cond.get_children();
+        if children.len() != 2 {
+            return None;
+        }
+        // Normalize to `handle <op> const`, flipping the op if the constant is
+        // on the left-hand side.
+        let (sig, c) = if self.is_pk_handle(&children[0]) {
+            (cond.get_sig(), Self::const_i64(&children[1])?)
+        } else if self.is_pk_handle(&children[1]) {
+            (flip_cmp_sig(cond.get_sig())?, Self::const_i64(&children[0])?)
+        } else {
+            return None;
+        };
+
+        let range = match sig {
+            ScalarFuncSig::EqInt => HandleRange { lo: c, hi: c },
+            // `handle > i64::MAX` is unsatisfiable; guard before `c + 1` wraps.
+            ScalarFuncSig::GtInt if c == i64::max_value() => HandleRange::empty(),
+            ScalarFuncSig::GtInt => HandleRange {
+                lo: c + 1,
+                hi: i64::max_value(),
+            },
+            ScalarFuncSig::GeInt => HandleRange {
+                lo: c,
+                hi: i64::max_value(),
+            },
+            // `handle < i64::MIN` is unsatisfiable; guard before `c - 1` wraps.
+            ScalarFuncSig::LtInt if c == i64::min_value() => HandleRange::empty(),
+            ScalarFuncSig::LtInt => HandleRange {
+                lo: i64::min_value(),
+                hi: c - 1,
+            },
+            ScalarFuncSig::LeInt => HandleRange {
+                lo: i64::min_value(),
+                hi: c,
+            },
+            _ => return None,
+        };
+        Some(range)
+    }
+
+    /// True when `expr` is a `ColumnRef` resolving to the pk-handle column.
+    fn is_pk_handle(&self, expr: &Expr) -> bool {
+        if expr.get_tp() != ExprType::ColumnRef {
+            return false;
+        }
+        match expr.get_val().read_i64() {
+            Ok(offset) => self
+                .columns
+                .get(offset as usize)
+                .map_or(false, |c| c.get_pk_handle()),
+            Err(_) => false,
+        }
+    }
+
+    /// Extracts an integer constant from `expr`, or `None` for other datums.
+    ///
+    /// A `Uint64` value above `i64::MAX` cannot be a valid handle bound, so it
+    /// is rejected rather than reinterpreted as a negative handle.
+    fn const_i64(expr: &Expr) -> Option<i64> {
+        match expr.get_tp() {
+            ExprType::Int64 => expr.get_val().read_i64().ok(),
+            ExprType::Uint64 => match expr.get_val().read_u64() {
+                Ok(v) if v <= i64::max_value() as u64 => Some(v as i64),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Flips a comparison signature so `const <op> handle` becomes the equivalent
+/// `handle <flipped op> const`.
+fn flip_cmp_sig(sig: ScalarFuncSig) -> Option<ScalarFuncSig> {
+    match sig {
+        ScalarFuncSig::EqInt => Some(ScalarFuncSig::EqInt),
+        ScalarFuncSig::GtInt => Some(ScalarFuncSig::LtInt),
+        ScalarFuncSig::GeInt => Some(ScalarFuncSig::LeInt),
+        ScalarFuncSig::LtInt => Some(ScalarFuncSig::GtInt),
+        ScalarFuncSig::LeInt => Some(ScalarFuncSig::GeInt),
+        _ => None,
+    }
+}
+
+/// Runs the handle-range analysis pass for a scan build.
+///
+/// It folds `pk_handle <op> const` predicates out of `conditions` into a
+/// single interval, intersects that interval with the request's `key_ranges`,
+/// and returns the tightened scan ranges together with the residual
+/// predicates that must remain in the `SelectionExecutor` filter. An empty
+/// intersection yields no ranges (an empty scan). `ExecutorsRunner` does not
+/// call it.
+pub fn tighten_key_ranges_by_handle(
+    columns: &[ColumnInfo],
+    conditions: Vec<Expr>,
+    table_id: i64,
+    key_ranges: &[KeyRange],
+) -> (Vec<KeyRange>, Vec<Expr>) {
+    let (range, residual) = HandleRangeDetector::new(columns).analyze(conditions);
+    (range.to_key_ranges(table_id, key_ranges), residual)
+}
+
+/// Runs the handle-range pushdown over a DAG's executor descriptors.
+///
+/// It locates the selection stage, folds its handle predicates into the scan's
+/// key ranges via [`tighten_key_ranges_by_handle`], and returns the tightened
+/// ranges together with the residual conditions that must stay in the
+/// `SelectionExecutor`. A DAG without a selection stage keeps its original
+/// ranges and an empty residual.
+pub fn tighten_key_ranges_for_dag(
+    columns: &[ColumnInfo],
+    table_id: i64,
+    key_ranges: &[KeyRange],
+    executors: &[tipb::Executor],
+) -> (Vec<KeyRange>, Vec<Expr>) {
+    for exec in executors {
+        if exec.get_tp() == ExecType::TypeSelection {
+            let conditions = exec.get_selection().get_conditions().to_vec();
+            return tighten_key_ranges_by_handle(columns, conditions, table_id, key_ranges);
+        }
+    }
+    (key_ranges.to_vec(), Vec::new())
+}
+
 #[derive(Debug)]
 pub struct OriginCols {
     pub handle: i64,
@@ -113,6 +418,55 @@ pub enum Row {
     Agg(AggCols),
 }
 
+/// The default number of rows packed into a single [`Chunk`].
+pub const BATCH_MAX_SIZE: usize = 64;
+
+/// Records where a single encoded row lives inside a [`Chunk`]'s data buffer.
+///
+/// Consumers slice rows back out by walking `length`s in order, so no
+/// re-parsing of the packed bytes is required.
+#[derive(Debug, Clone, Copy)]
+pub struct RowMeta {
+    pub handle: i64,
+    pub length: i64,
+}
+
+/// A fat-array accumulator that packs a batch of encoded rows into one
+/// contiguous byte buffer.
+///
+/// `data` holds the concatenated encodings of every row and `meta` keeps a
+/// parallel [`RowMeta`] per row, amortizing the per-row allocation and
+/// encoding overhead of emitting rows one at a time.
+#[derive(Debug, Default)]
+pub struct Chunk {
+    data: Vec<u8>,
+    meta: Vec<RowMeta>,
+}
+
+impl Chunk {
+    /// Appends one already-encoded row, recording its handle and length.
+    fn append_row(&mut self, handle: i64, value: &[u8]) {
+        self.meta.push(RowMeta {
+            handle,
+            length: value.len() as i64,
+        });
+        self.data.extend_from_slice(value);
+    }
+
+    /// Returns true once the chunk has reached the requested batch size.
+    fn is_full(&self, batch_rows: usize) -> bool {
+        self.meta.len() >= batch_rows
+    }
+
+    pub fn get_data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn get_meta(&self) -> &[RowMeta] {
+        &self.meta
+    }
+}
+
 impl Row {
     pub fn origin(handle: i64, data: RowColsDict, cols: Arc<Vec<ColumnInfo>>) -> Row {
         Row::Origin(OriginCols::new(handle, data, cols))
@@ -172,6 +526,41 @@ impl OriginCols {
         Ok(res)
     }
 
+    // Like `get_binary_cols`, but skips decoding any column whose id is not in
+    // `needed`, emitting a NULL datum placeholder for it instead. The set is
+    // expected to already retain the pk-handle and NOT_NULL columns (see
+    // `referenced_column_ids`), so those are always materialized.
+    pub fn get_binary_cols_pruned(&self, needed: &HashSet<i64>) -> Result<Vec<Vec<u8>>> {
+        let mut res = Vec::with_capacity(self.cols.len());
+        for col in self.cols.iter() {
+            if col.get_pk_handle() {
+                let v = util::get_pk(col, self.handle);
+                let bt = box_try!(datum::encode_value(&[v]));
+                res.push(bt);
+                continue;
+            }
+            let col_id = col.get_column_id();
+            if !needed.contains(&col_id) {
+                res.push(box_try!(datum::encode_value(&[Datum::Null])));
+                continue;
+            }
+            let value = match self.data.get(col_id) {
+                None if col.has_default_val() => col.get_default_val().to_vec(),
+                None if col.as_accessor().flag().contains(FieldTypeFlag::NOT_NULL) => {
+                    return Err(other_err!(
+                        "column {} of {} is missing",
+                        col_id,
+                        self.handle
+                    ));
+                }
+                None => box_try!(datum::encode_value(&[Datum::Null])),
+                Some(bs) => bs.to_vec(),
+            };
+            res.push(value);
+        }
+        Ok(res)
+    }
+
     pub fn get_binary(&self, output_offsets: &[u32]) -> Result<Vec<u8>> {
         // TODO capacity is not enough
         let mut values = Vec::with_capacity(self.data.value.len());
@@ -250,6 +639,39 @@ pub trait Executor: Send {
 
     fn next(&mut self) -> Result<Option<Row>>;
 
+    /// Drains up to `batch_rows` rows from `next` into a single [`Chunk`].
+    ///
+    /// Each `Row::Origin` is serialized via `get_binary(output_offsets)` and
+    /// each `Row::Agg` via `get_binary()`, and the resulting bytes are packed
+    /// into the chunk's contiguous data buffer. Returns the chunk and whether
+    /// the source was drained (i.e. `next` yielded `None`).
+    ///
+    /// `batch_rows` must be at least 1; a batch size of 0 can never fill, so
+    /// it is clamped to 1 to keep drain-until-done callers from spinning on an
+    /// endless run of empty, non-drained chunks.
+    fn next_batch(
+        &mut self,
+        output_offsets: &[u32],
+        batch_rows: usize,
+    ) -> Result<(Chunk, bool)> {
+        let batch_rows = batch_rows.max(1);
+        let mut chunk = Chunk::default();
+        while !chunk.is_full(batch_rows) {
+            match self.next()? {
+                Some(Row::Origin(row)) => {
+                    let value = row.get_binary(output_offsets)?;
+                    chunk.append_row(row.handle, &value);
+                }
+                Some(Row::Agg(row)) => {
+                    let value = row.get_binary()?;
+                    chunk.append_row(0, &value);
+                }
+                None => return Ok((chunk, true)),
+            }
+        }
+        Ok((chunk, false))
+    }
+
     fn collect_exec_stats(&mut self, dest: &mut ExecuteStats);
 
     fn collect_storage_stats(&mut self, dest: &mut Self::StorageStats);
@@ -349,19 +771,45 @@ impl<T: Executor + ?Sized> Executor for Box<T> {
     }
 }
 
+/// Drains `exec` into a sequence of [`Chunk`]s of at most `batch_rows` rows
+/// each: it loops `next_batch` until the source is drained, skipping the
+/// trailing empty chunk a perfectly-aligned source produces. `ExecutorsRunner`
+/// does not call it.
+pub fn collect_chunks<E: Executor + ?Sized>(
+    exec: &mut E,
+    output_offsets: &[u32],
+    batch_rows: usize,
+) -> Result<Vec<Chunk>> {
+    let mut chunks = Vec::new();
+    loop {
+        let (chunk, drained) = exec.next_batch(output_offsets, batch_rows)?;
+        if !chunk.get_meta().is_empty() {
+            chunks.push(chunk);
+        }
+        if drained {
+            return Ok(chunks);
+        }
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
-    use super::{Executor, TableScanExecutor};
+    use super::{collect_chunks, dag_referenced_column_ids, referenced_column_ids, Executor};
+    use super::{tighten_key_ranges_by_handle, ExecuteStats, EvalWarnings, Row};
+    use super::{tighten_key_ranges_for_dag, HandleRange, HandleRangeDetector};
+    use super::{IntervalRange, TableScanExecutor};
     use crate::codec::{datum, table, Datum};
     use crate::storage::fixture::FixtureStorage;
+    use crate::Result;
     use codec::prelude::NumberEncoder;
     use kvproto::coprocessor::KeyRange;
-    use tidb_query_datatype::{FieldTypeAccessor, FieldTypeTp};
+    use tidb_query_datatype::{FieldTypeAccessor, FieldTypeFlag, FieldTypeTp};
     use tikv_util::collections::HashMap;
     use tikv_util::map;
     use tipb::ColumnInfo;
+    use tipb::ScalarFuncSig;
     use tipb::TableScan;
-    use tipb::{Expr, ExprType};
+    use tipb::{Aggregation, ExecType, Expr, ExprType, Selection};
 
     pub fn build_expr(tp: ExprType, id: Option<i64>, child: Option<Expr>) -> Expr {
         let mut expr = Expr::default();
@@ -517,4 +965,278 @@ pub mod tests {
         let key_ranges = key_ranges.unwrap_or_else(|| vec![get_range(tid, 0, i64::max_value())]);
         Box::new(TableScanExecutor::table_scan(table_scan, key_ranges, storage, false).unwrap())
     }
+
+    /// An executor that replays a fixed list of rows, used to drive the
+    /// batched `next_batch` path without a backing store.
+    struct VecExecutor {
+        // rows in reverse emission order so `pop` yields them front-to-back.
+        rows: Vec<Row>,
+    }
+
+    impl VecExecutor {
+        fn new(mut rows: Vec<Row>) -> VecExecutor {
+            rows.reverse();
+            VecExecutor { rows }
+        }
+    }
+
+    impl Executor for VecExecutor {
+        type StorageStats = ();
+
+        fn next(&mut self) -> Result<Option<Row>> {
+            Ok(self.rows.pop())
+        }
+
+        fn collect_exec_stats(&mut self, _dest: &mut ExecuteStats) {}
+
+        fn collect_storage_stats(&mut self, _dest: &mut ()) {}
+
+        fn get_len_of_columns(&self) -> usize {
+            0
+        }
+
+        fn take_eval_warnings(&mut self) -> Option<EvalWarnings> {
+            None
+        }
+
+        fn take_scanned_range(&mut self) -> IntervalRange {
+            unreachable!("VecExecutor does not scan a range")
+        }
+    }
+
+    #[test]
+    fn test_next_batch_packs_and_drains() {
+        let mut exec = VecExecutor::new(vec![
+            Row::agg(vec![Datum::I64(1)], vec![]),
+            Row::agg(vec![Datum::I64(2)], vec![]),
+            Row::agg(vec![Datum::I64(3)], vec![]),
+        ]);
+
+        // A full batch stops at `batch_rows` and is not yet drained.
+        let (chunk, drained) = exec.next_batch(&[], 2).unwrap();
+        assert!(!drained);
+        assert_eq!(chunk.get_meta().len(), 2);
+        // The data buffer is exactly the concatenation of each row's bytes.
+        let packed: i64 = chunk.get_meta().iter().map(|m| m.length).sum();
+        assert_eq!(packed as usize, chunk.get_data().len());
+
+        // The remaining row drains the source.
+        let (chunk, drained) = exec.next_batch(&[], 2).unwrap();
+        assert!(drained);
+        assert_eq!(chunk.get_meta().len(), 1);
+
+        // An already-drained source yields an empty, drained chunk.
+        let (chunk, drained) = exec.next_batch(&[], 2).unwrap();
+        assert!(drained);
+        assert!(chunk.get_meta().is_empty());
+        assert!(chunk.get_data().is_empty());
+    }
+
+    #[test]
+    fn test_collect_chunks_emits_vec_of_chunks() {
+        let exec = VecExecutor::new(vec![
+            Row::agg(vec![Datum::I64(1)], vec![]),
+            Row::agg(vec![Datum::I64(2)], vec![]),
+            Row::agg(vec![Datum::I64(3)], vec![]),
+            Row::agg(vec![Datum::I64(4)], vec![]),
+        ]);
+        let mut exec = exec;
+        // Four rows at two per chunk drain into exactly two full chunks, with
+        // no trailing empty chunk.
+        let chunks = collect_chunks(&mut exec, &[], 2).unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks.iter().all(|c| c.get_meta().len() == 2));
+
+        // An empty source yields no chunks at all.
+        let mut empty = VecExecutor::new(vec![]);
+        assert!(collect_chunks(&mut empty, &[], 2).unwrap().is_empty());
+    }
+
+    fn pk_cmp_expr(sig: ScalarFuncSig, col_offset: i64, constant: Datum) -> Expr {
+        let mut col = Expr::default();
+        col.set_tp(ExprType::ColumnRef);
+        col.mut_val().write_i64(col_offset).unwrap();
+
+        let mut cst = Expr::default();
+        match constant {
+            Datum::I64(v) => {
+                cst.set_tp(ExprType::Int64);
+                cst.mut_val().write_i64(v).unwrap();
+            }
+            Datum::U64(v) => {
+                cst.set_tp(ExprType::Uint64);
+                cst.mut_val().write_u64(v).unwrap();
+            }
+            _ => unreachable!(),
+        }
+
+        let mut expr = Expr::default();
+        expr.set_tp(ExprType::ScalarFunc);
+        expr.set_sig(sig);
+        expr.mut_children().push(col);
+        expr.mut_children().push(cst);
+        expr
+    }
+
+    fn pk_handle_cols() -> Vec<ColumnInfo> {
+        let mut pk = new_col_info(1, FieldTypeTp::LongLong);
+        pk.set_pk_handle(true);
+        vec![pk]
+    }
+
+    #[test]
+    fn test_handle_range_detector_folds_predicate() {
+        let cols = pk_handle_cols();
+        let (range, residual) = HandleRangeDetector::new(&cols)
+            .analyze(vec![pk_cmp_expr(ScalarFuncSig::GtInt, 0, Datum::I64(5))]);
+        assert_eq!(
+            range,
+            HandleRange {
+                lo: 6,
+                hi: i64::max_value()
+            }
+        );
+        // A fully converted predicate is dropped from the residual filter.
+        assert!(residual.is_empty());
+        // The interval maps to exactly one tightened scan range.
+        assert_eq!(
+            range
+                .to_key_ranges(1, &[get_range(1, 0, i64::max_value())])
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_handle_range_detector_saturates_to_empty() {
+        let cols = pk_handle_cols();
+        // `handle > i64::MAX` and `handle < i64::MIN` are unsatisfiable and
+        // must collapse to an empty scan rather than a single-row interval.
+        let outer = vec![get_range(1, 0, i64::max_value())];
+        let (gt, _) = HandleRangeDetector::new(&cols).analyze(vec![pk_cmp_expr(
+            ScalarFuncSig::GtInt,
+            0,
+            Datum::I64(i64::max_value()),
+        )]);
+        assert!(gt.to_key_ranges(1, &outer).is_empty());
+        let (lt, _) = HandleRangeDetector::new(&cols).analyze(vec![pk_cmp_expr(
+            ScalarFuncSig::LtInt,
+            0,
+            Datum::I64(i64::min_value()),
+        )]);
+        assert!(lt.to_key_ranges(1, &outer).is_empty());
+    }
+
+    #[test]
+    fn test_handle_range_detector_rejects_out_of_range_unsigned() {
+        let cols = pk_handle_cols();
+        // A Uint64 constant above i64::MAX cannot be a handle bound, so the
+        // predicate stays in the residual filter and leaves the range open.
+        let (range, residual) = HandleRangeDetector::new(&cols).analyze(vec![pk_cmp_expr(
+            ScalarFuncSig::LeInt,
+            0,
+            Datum::U64(i64::max_value() as u64 + 1),
+        )]);
+        assert_eq!(residual.len(), 1);
+        assert_eq!(range, HandleRange::full());
+    }
+
+    #[test]
+    fn test_tighten_key_ranges_by_handle_intersects_and_drops() {
+        let cols = pk_handle_cols();
+        let outer = vec![get_range(1, 0, 100)];
+        // `handle >= 10` tightens the scan and is fully converted away.
+        let (ranges, residual) = tighten_key_ranges_by_handle(
+            &cols,
+            vec![pk_cmp_expr(ScalarFuncSig::GeInt, 0, Datum::I64(10))],
+            1,
+            &outer,
+        );
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0].get_start(), table::encode_row_key(1, 10).as_slice());
+        assert_eq!(ranges[0].get_end(), outer[0].get_end());
+        assert!(residual.is_empty());
+
+        // An interval disjoint from the request ranges yields an empty scan.
+        let (empty, _) = tighten_key_ranges_by_handle(
+            &cols,
+            vec![pk_cmp_expr(ScalarFuncSig::GeInt, 0, Datum::I64(1000))],
+            1,
+            &outer,
+        );
+        assert!(empty.is_empty());
+    }
+
+    fn selection_executor(conditions: Vec<Expr>) -> tipb::Executor {
+        let mut sel = Selection::default();
+        sel.set_conditions(conditions.into());
+        let mut exec = tipb::Executor::default();
+        exec.set_tp(ExecType::TypeSelection);
+        exec.set_selection(sel);
+        exec
+    }
+
+    #[test]
+    fn test_tighten_key_ranges_for_dag_folds_selection() {
+        let cols = pk_handle_cols();
+        let outer = vec![get_range(1, 0, 100)];
+        let executors = vec![selection_executor(vec![pk_cmp_expr(
+            ScalarFuncSig::GeInt,
+            0,
+            Datum::I64(10),
+        )])];
+        let (ranges, residual) = tighten_key_ranges_for_dag(&cols, 1, &outer, &executors);
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0].get_start(), table::encode_row_key(1, 10).as_slice());
+        assert!(residual.is_empty());
+
+        // With no selection stage the request ranges pass through unchanged.
+        let (ranges, residual) = tighten_key_ranges_for_dag(&cols, 1, &outer, &[]);
+        assert_eq!(ranges, outer);
+        assert!(residual.is_empty());
+    }
+
+    #[test]
+    fn test_referenced_column_ids_prunes_unreferenced() {
+        let mut pk = new_col_info(1, FieldTypeTp::LongLong);
+        pk.set_pk_handle(true);
+        let nullable = new_col_info(2, FieldTypeTp::VarChar);
+        let mut not_null = new_col_info(3, FieldTypeTp::LongLong);
+        not_null.as_mut_accessor().set_flag(FieldTypeFlag::NOT_NULL);
+        let cols = vec![pk, nullable, not_null];
+
+        // The selection only references the pk-handle column (offset 0); the
+        // group-by/agg/topn expression groups are empty for this query.
+        let conditions = vec![build_expr(ExprType::ColumnRef, Some(0), None)];
+        let ids = referenced_column_ids(&cols, &[], &conditions, &[], &[], &[]).unwrap();
+
+        assert!(ids.contains(&1), "pk-handle column must be retained");
+        assert!(!ids.contains(&2), "unreferenced nullable column is pruned");
+        assert!(ids.contains(&3), "NOT_NULL column must be retained");
+    }
+
+    #[test]
+    fn test_dag_referenced_column_ids_unions_stages() {
+        let mut pk = new_col_info(1, FieldTypeTp::LongLong);
+        pk.set_pk_handle(true);
+        let col2 = new_col_info(2, FieldTypeTp::VarChar);
+        let col3 = new_col_info(3, FieldTypeTp::LongLong);
+        let col4 = new_col_info(4, FieldTypeTp::LongLong);
+        let cols = vec![pk, col2, col3, col4];
+
+        // Selection references offset 1 (col id 2); aggregation groups by
+        // offset 2 (col id 3). Offset 3 (col id 4) is touched by nobody.
+        let selection = selection_executor(vec![build_expr(ExprType::ColumnRef, Some(1), None)]);
+        let mut agg = Aggregation::default();
+        agg.set_group_by(vec![build_expr(ExprType::ColumnRef, Some(2), None)].into());
+        let mut agg_exec = tipb::Executor::default();
+        agg_exec.set_tp(ExecType::TypeAggregation);
+        agg_exec.set_aggregation(agg);
+
+        let ids = dag_referenced_column_ids(&cols, &[], &[selection, agg_exec]).unwrap();
+        assert!(ids.contains(&1), "pk-handle column must be retained");
+        assert!(ids.contains(&2), "selection column must be retained");
+        assert!(ids.contains(&3), "group-by column must be retained");
+        assert!(!ids.contains(&4), "column touched by nobody is pruned");
+    }
 }